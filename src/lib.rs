@@ -3,6 +3,7 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 extern crate libc;
+extern crate log;
 
 use libc::{c_int, c_void, size_t};
 
@@ -14,6 +15,7 @@ use std::u64;
 use std::fmt;
 use std::ptr;
 use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 // Opaque data type for journal handle for use in ffi calls
 pub enum SdJournal {}
@@ -30,23 +32,22 @@ enum SdJournalOpen {
     */
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct ClibraryError {
     pub message: String,
     pub return_code: c_int,
-    pub err_reason: String,
+    pub source: std::io::Error,
 }
 
 impl fmt::Display for ClibraryError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{} (rc={}, errno msg={})",
-               self.message, self.return_code, self.err_reason)
+        write!(f, "{} (rc={}, {})", self.message, self.return_code, self.source)
     }
 }
 
 impl std::error::Error for ClibraryError {
-    fn description(&self) -> &str {
-        &self.message
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
     }
 }
 
@@ -55,9 +56,119 @@ impl ClibraryError {
         ClibraryError {
             message: error_msg,
             return_code: return_code,
-            err_reason: error_string(-return_code)
+            source: std::io::Error::from_raw_os_error(-return_code),
         }
     }
+
+    // The underlying ErrorKind, eg. to tell ENOENT apart from other failures without matching
+    // on the raw return code.
+    pub fn kind(&self) -> std::io::ErrorKind {
+        self.source.kind()
+    }
+}
+
+impl From<std::io::Error> for ClibraryError {
+    fn from(err: std::io::Error) -> ClibraryError {
+        let return_code = -err.raw_os_error().unwrap_or(0);
+        ClibraryError {
+            message: String::from("I/O error"),
+            return_code,
+            source: err,
+        }
+    }
+}
+
+// A journal field's value, as retrieved with get_field()/the entry iterator. Most fields are
+// plain text, but some (eg. COREDUMP, or a MESSAGE containing control bytes) aren't valid UTF8,
+// so callers need a way to get at the raw bytes instead of panicking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldValue {
+    Utf8(String),
+    Raw(Vec<u8>),
+}
+
+// Journal fields come back from sd_journal_get_data/sd_journal_enumerate_data as a single
+// "FIELD=value" blob; split it on the first '=' rather than assuming a fixed offset.
+fn split_field(data: &[u8]) -> (&[u8], &[u8]) {
+    match data.iter().position(|&b| b == b'=') {
+        Some(pos) => (&data[..pos], &data[pos + 1..]),
+        None => (data, &data[0..0]),
+    }
+}
+
+fn field_value(bytes: &[u8]) -> FieldValue {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => FieldValue::Utf8(s.to_owned()),
+        Err(_) => FieldValue::Raw(bytes.to_vec()),
+    }
+}
+
+// A 128-bit ID in the form journald uses for MESSAGE_ID, the machine ID and the boot ID: a
+// sequence of 16 bytes, formatted as 32 lowercase hex digits with no separators.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Id128([u8; 16]);
+
+impl Id128 {
+    // Generate a new random (type 4 style) ID, suitable for a MESSAGE_ID that callers want to
+    // mint once and hard-code.
+    pub fn random() -> Result<Id128, ClibraryError> {
+        let mut id = Id128([0; 16]);
+        let rc = unsafe { sd_id128_randomize(&mut id as *mut Id128) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_id128_randomize"), rc));
+        }
+        Ok(id)
+    }
+
+    // The ID identifying this machine, as found in /etc/machine-id.
+    pub fn machine() -> Result<Id128, ClibraryError> {
+        let mut id = Id128([0; 16]);
+        let rc = unsafe { sd_id128_get_machine(&mut id as *mut Id128) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_id128_get_machine"), rc));
+        }
+        Ok(id)
+    }
+
+    // The ID identifying the current boot.
+    pub fn boot() -> Result<Id128, ClibraryError> {
+        let mut id = Id128([0; 16]);
+        let rc = unsafe { sd_id128_get_boot(&mut id as *mut Id128) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_id128_get_boot"), rc));
+        }
+        Ok(id)
+    }
+
+    // Parse the 32-char lowercase hex form journald uses everywhere IDs show up.
+    pub fn parse(s: &str) -> Result<Id128, ClibraryError> {
+        if s.len() != 32 {
+            return Err(ClibraryError::new(
+                format!("Invalid Id128 string (expected 32 hex chars, got {})", s.len()),
+                -libc::EINVAL));
+        }
+        // from_str_radix would also accept a leading '+', which isn't part of the hex form.
+        if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ClibraryError::new(format!("Invalid Id128 string: {}", s), -libc::EINVAL));
+        }
+
+        let value = u128::from_str_radix(s, 16).map_err(|_| {
+            ClibraryError::new(format!("Invalid Id128 string: {}", s), -libc::EINVAL)
+        })?;
+        Ok(Id128(value.to_be_bytes()))
+    }
+
+    // Format as a ready-to-use add_match() expression, eg. id.as_match("_MACHINE_ID").
+    pub fn as_match(&self, field: &str) -> String {
+        format!("{}={}", field, self)
+    }
+}
+
+impl fmt::Display for Id128 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{:032x}", u128::from_be_bytes(self.0))
+    }
 }
 
 // Wakeup event types
@@ -67,6 +178,17 @@ enum SdJournalWait {
     Invalidate = 2,
 }
 
+// Result of a non-blocking Journal::process() call, mirroring sd_journal_process's return value.
+#[derive(Debug, PartialEq, Eq)]
+pub enum JournalState {
+    // Nothing changed since the last process() call.
+    Nop,
+    // New entries were appended.
+    Append,
+    // The journal files were rotated or otherwise invalidated; any cursor should be re-checked.
+    Invalidate,
+}
+
 #[link(name = "systemd")]
 extern {
     fn sd_journal_open(ret: *mut *mut SdJournal, flags: c_int) -> c_int;
@@ -79,35 +201,32 @@ extern {
     fn sd_journal_wait(j: *mut SdJournal, timeout_usec: u64) -> c_int;
     fn sd_journal_seek_tail(j: *mut SdJournal) -> c_int;
 
+    fn sd_journal_get_fd(j: *mut SdJournal) -> c_int;
+    fn sd_journal_get_events(j: *mut SdJournal) -> c_int;
+    fn sd_journal_get_timeout(j: *mut SdJournal, timeout_usec: *mut u64) -> c_int;
+    fn sd_journal_process(j: *mut SdJournal) -> c_int;
+
+    fn sd_journal_add_match(j: *mut SdJournal, data: *const c_void, size: size_t) -> c_int;
+    fn sd_journal_add_conjunction(j: *mut SdJournal) -> c_int;
+    fn sd_journal_add_disjunction(j: *mut SdJournal) -> c_int;
+    fn sd_journal_flush_matches(j: *mut SdJournal);
+
+    fn sd_journal_seek_head(j: *mut SdJournal) -> c_int;
+    fn sd_journal_seek_realtime_usec(j: *mut SdJournal, usec: u64) -> c_int;
+    fn sd_journal_get_cursor(j: *mut SdJournal, cursor: *mut *mut c_char) -> c_int;
+    fn sd_journal_seek_cursor(j: *mut SdJournal, cursor: *const c_char) -> c_int;
+
     fn sd_journal_send(message: *const u8, ...) -> c_int;
+    fn sd_journal_sendv(iv: *const libc::iovec, n: c_int) -> c_int;
 
     fn sd_journal_restart_data(j: *mut SdJournal);
     fn sd_journal_enumerate_data(j: *mut SdJournal,
                                  data: *mut *mut c_void,
                                  length: *mut size_t) -> c_int;
-}
-
-// Copied and pasted from https://github.com/rust-lang/rust/blob/master/src/libstd/sys/unix/os.rs
-// if I can figure out how to call it I will delete this!!!
-pub fn error_string(errno: i32) -> String {
-    extern {
-        #[cfg_attr(any(target_os = "linux", target_env = "newlib"),
-        link_name = "__xpg_strerror_r")]
-        fn strerror_r(errnum: c_int, buf: *mut c_char,
-                      buflen: libc::size_t) -> c_int;
-    }
-
-    let mut buf = [0 as c_char; 128];
 
-    let p = buf.as_mut_ptr();
-    unsafe {
-        if strerror_r(errno as c_int, p, buf.len()) < 0 {
-            panic!("strerror_r failure");
-        }
-
-        let p = p as *const _;
-        std::str::from_utf8(CStr::from_ptr(p).to_bytes()).unwrap().to_owned()
-    }
+    fn sd_id128_randomize(ret: *mut Id128) -> c_int;
+    fn sd_id128_get_machine(ret: *mut Id128) -> c_int;
+    fn sd_id128_get_boot(ret: *mut Id128) -> c_int;
 }
 
 pub struct Journal {
@@ -137,34 +256,31 @@ impl Journal {
         }
     }
 
-    fn get_log_entry(&mut self, key: &'static str) -> Result<String, ClibraryError> {
+    pub fn get_field(&mut self, key: &str) -> Result<FieldValue, ClibraryError> {
         let mut x = 0 as *mut c_void;
         let mut len = 0 as size_t;
         let field = CString::new(key).unwrap();
 
-        let log_msg: String;
         let rc = unsafe {
             sd_journal_get_data(self.handle, field.as_ptr(),
                                 (&mut x) as *mut _ as *mut *mut c_void,
                                 &mut len)
         };
         if rc == 0 {
-            let slice = unsafe { slice::from_raw_parts(x as *const u8, len) };
-            log_msg = String::from_utf8(slice[8..len].to_vec()).unwrap();
+            let data = unsafe { slice::from_raw_parts(x as *const u8, len) };
+            let (_, value) = split_field(data);
+            Ok(field_value(value))
         } else {
-            if rc == -2 {       // ENOENT, TODO: Is there a rust constant for this?
-                // TODO: Is there a better way to handle a key not being found?
-                log_msg = String::from("");
+            let err = ClibraryError::new(String::from("Error on sd_journal_get_data"), rc);
+            if err.kind() == std::io::ErrorKind::NotFound {
+                Ok(FieldValue::Utf8(String::new()))
             } else {
-                return Err(ClibraryError::new(String::from("Error on sd_journal_get_data"),
-                                              rc));
+                Err(err)
             }
         }
-
-        Ok(log_msg)
     }
 
-    fn get_log_entry_map(&mut self) -> Result<HashMap<String, String>, ClibraryError> {
+    fn get_log_entry_map(&mut self) -> Result<HashMap<String, FieldValue>, ClibraryError> {
         let mut result = HashMap::new();
 
         // Re-set for the enumerator
@@ -181,17 +297,12 @@ impl Journal {
             };
 
             if rc > 0 {
-                let slice = unsafe { slice::from_raw_parts(x as *const u8, len) };
-                let log_msg = String::from_utf8(slice[0..len].to_vec()).unwrap();
-
-                let m = log_msg.find('=');
-                match m {
-                    Some(m) => {
-                        let key = String::from_utf8(slice[0..m].to_vec()).unwrap();
-                        let value = String::from_utf8(slice[((m + 1)..len)].to_vec()).unwrap();
-                        result.insert(key, value);
-                    }
-                    None => ()
+                let data = unsafe { slice::from_raw_parts(x as *const u8, len) };
+                let (key, value) = split_field(data);
+
+                // Field names are always plain ASCII; only the value may be binary.
+                if let Ok(key) = std::str::from_utf8(key) {
+                    result.insert(key.to_owned(), field_value(value));
                 }
             } else {
                 if rc < 0 {
@@ -215,11 +326,184 @@ impl Journal {
         }
         Ok(true)
     }
+
+    // Restrict subsequent reads to entries matching "FIELD=value", eg. "_SYSTEMD_UNIT=sshd.service".
+    // Matches added back to back are combined with a logical AND; use add_disjunction() to start
+    // a new OR'd group, mirroring sd_journal_add_match's own semantics.
+    pub fn add_match(&mut self, expr: &str) -> Result<bool, ClibraryError> {
+        let rc = unsafe {
+            sd_journal_add_match(self.handle, expr.as_ptr() as *const c_void, expr.len() as size_t)
+        };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_add_match"), rc));
+        }
+        Ok(true)
+    }
+
+    // Start a new AND'd term: everything added before this (including any OR'd groups) is
+    // ANDed with the matches added after it.
+    pub fn add_conjunction(&mut self) -> Result<bool, ClibraryError> {
+        let rc = unsafe { sd_journal_add_conjunction(self.handle) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_add_conjunction"), rc));
+        }
+        Ok(true)
+    }
+
+    // Start a new OR'd term: matches added after this are ANDed with each other, and the whole
+    // group is ORed with whatever came before.
+    pub fn add_disjunction(&mut self) -> Result<bool, ClibraryError> {
+        let rc = unsafe { sd_journal_add_disjunction(self.handle) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_add_disjunction"), rc));
+        }
+        Ok(true)
+    }
+
+    // Remove all matches added with add_match()/add_conjunction()/add_disjunction().
+    pub fn flush_matches(&mut self) {
+        unsafe { sd_journal_flush_matches(self.handle) };
+    }
+
+    pub fn seek_head(&mut self) -> Result<bool, ClibraryError> {
+        let rc = unsafe { sd_journal_seek_head(self.handle) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_seek_head"), rc));
+        }
+        Ok(true)
+    }
+
+    // Seek to the first entry at or after the given realtime (wall clock) timestamp, in
+    // microseconds since the epoch.
+    pub fn seek_realtime(&mut self, usec: u64) -> Result<bool, ClibraryError> {
+        let rc = unsafe { sd_journal_seek_realtime_usec(self.handle, usec) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_seek_realtime_usec"), rc));
+        }
+        Ok(true)
+    }
+
+    // A cursor string identifying the current entry, which can be saved and passed to
+    // seek_cursor() later (eg. after a restart) to resume reading from the same point.
+    pub fn cursor(&mut self) -> Result<String, ClibraryError> {
+        let mut cursor_ptr: *mut c_char = ptr::null_mut();
+        let rc = unsafe { sd_journal_get_cursor(self.handle, &mut cursor_ptr) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_get_cursor"), rc));
+        }
+
+        let cursor = unsafe { CStr::from_ptr(cursor_ptr) }.to_string_lossy().into_owned();
+        unsafe { libc::free(cursor_ptr as *mut c_void) };
+        Ok(cursor)
+    }
+
+    pub fn seek_cursor(&mut self, cursor: &str) -> Result<bool, ClibraryError> {
+        let cursor_cstr = CString::new(cursor).unwrap();
+        let rc = unsafe { sd_journal_seek_cursor(self.handle, cursor_cstr.as_ptr()) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_seek_cursor"), rc));
+        }
+        Ok(true)
+    }
+
+    // Poll events (eg. libc::POLLIN) to watch for on the fd returned by as_raw_fd().
+    pub fn events(&self) -> Result<c_int, ClibraryError> {
+        let rc = unsafe { sd_journal_get_events(self.handle) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_get_events"), rc));
+        }
+        Ok(rc)
+    }
+
+    // How long the caller may wait on the fd before calling process() again, or None if there's
+    // no timeout to honor.
+    pub fn timeout_usec(&self) -> Result<Option<u64>, ClibraryError> {
+        let mut timeout_usec = 0u64;
+        let rc = unsafe { sd_journal_get_timeout(self.handle, &mut timeout_usec) };
+        if rc < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_get_timeout"), rc));
+        }
+        if timeout_usec == u64::MAX {
+            Ok(None)
+        } else {
+            Ok(Some(timeout_usec))
+        }
+    }
+
+    // Non-blocking equivalent of the work sd_journal_wait does inside the blocking Iterator
+    // impl below: tells the caller what changed since the last call so it knows whether to
+    // re-read entries, without ever blocking. Meant to be called once the fd from as_raw_fd()
+    // has been reported readable by an external event loop.
+    pub fn process(&mut self) -> Result<JournalState, ClibraryError> {
+        let rc = unsafe { sd_journal_process(self.handle) };
+        if rc == SdJournalWait::Nop as i32 {
+            Ok(JournalState::Nop)
+        } else if rc == SdJournalWait::Append as i32 {
+            Ok(JournalState::Append)
+        } else if rc == SdJournalWait::Invalidate as i32 {
+            Ok(JournalState::Invalidate)
+        } else {
+            Err(ClibraryError::new(String::from("Error on sd_journal_process"), rc))
+        }
+    }
+
+    // Non-blocking equivalent of the Iterator impl below: returns Ok(None) instead of blocking
+    // in sd_journal_wait when there's no entry available right now.
+    pub fn try_next(&mut self) -> Result<Option<HashMap<String, FieldValue>>, ClibraryError> {
+        let log_entry = unsafe { sd_journal_next(self.handle) };
+        if log_entry < 0 {
+            return Err(ClibraryError::new(String::from("Error on sd_journal_next"), log_entry));
+        }
+        if log_entry == 0 {
+            return Ok(None);
+        }
+
+        self.get_log_entry_map().map(Some)
+    }
+}
+
+impl AsRawFd for Journal {
+    fn as_raw_fd(&self) -> RawFd {
+        unsafe { sd_journal_get_fd(self.handle) }
+    }
+}
+
+// Send an arbitrary set of fields to the journal via sd_journal_sendv, eg.
+// send_journal(&[("MESSAGE", "hello"), ("CODE_FILE", file!())]).
+//
+// Each (key, value) pair is formatted as "KEY=VALUE" and handed to journald as an iovec, so
+// unlike send_journal_basic this isn't limited to a fixed set of fields and doesn't require the
+// value to be a NUL-free C string.
+pub fn send_journal(fields: &[(&str, &str)]) -> Result<bool, ClibraryError> {
+    let buffers: Vec<Vec<u8>> = fields.iter()
+        .map(|&(key, value)| {
+            let mut buf = Vec::with_capacity(key.len() + 1 + value.len());
+            buf.extend_from_slice(key.as_bytes());
+            buf.push(b'=');
+            buf.extend_from_slice(value.as_bytes());
+            buf
+        })
+        .collect();
+
+    // iovecs point into `buffers`, so it must outlive the sd_journal_sendv call below.
+    let iovecs: Vec<libc::iovec> = buffers.iter()
+        .map(|buf| {
+            libc::iovec {
+                iov_base: buf.as_ptr() as *mut c_void,
+                iov_len: buf.len() as size_t,
+            }
+        })
+        .collect();
+
+    let rc = unsafe { sd_journal_sendv(iovecs.as_ptr(), iovecs.len() as c_int) };
+
+    if rc < 0 {
+        return Err(ClibraryError::new(String::from("Error on sd_journal_sendv"), rc));
+    }
+    Ok(true)
 }
 
-// TODO: Not sure how to handle the case where we need to send an arbitrary list of additional
-// details without providing a function wrapper for each.
-pub fn send_journal_basic(message_id: &'static str,
+pub fn send_journal_basic(message_id: Id128,
                           message: String, source: String, source_man: String, device: String,
                           device_id: String, state: String,
                           priority: u8, details: String) -> Result<bool, ClibraryError> {
@@ -271,9 +555,9 @@ pub fn send_journal_basic(message_id: &'static str,
 }
 
 impl Iterator for Journal {
-    type Item = Result<HashMap<String, String>, ClibraryError>;
+    type Item = Result<HashMap<String, FieldValue>, ClibraryError>;
 
-    fn next(&mut self) -> Option<Result<HashMap<String, String>, ClibraryError>> {
+    fn next(&mut self) -> Option<Result<HashMap<String, FieldValue>, ClibraryError>> {
 
         loop {
             let log_entry = unsafe { sd_journal_next(self.handle) };
@@ -305,3 +589,54 @@ impl Iterator for Journal {
         }
     }
 }
+
+// A `log::Log` implementation that sends records straight to the journal, so the usual
+// `info!`/`warn!`/`error!` macros end up as journal entries without any manual send_journal
+// calls at the call site.
+pub struct JournalLog;
+
+impl log::Log for JournalLog {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let priority = match record.level() {
+            log::Level::Error => 3,
+            log::Level::Warn => 4,
+            log::Level::Info => 6,
+            log::Level::Debug | log::Level::Trace => 7,
+        };
+
+        let message = format!("{}", record.args());
+        let priority = priority.to_string();
+        let line = record.line().unwrap_or(0).to_string();
+
+        let mut fields: Vec<(&str, &str)> = vec![
+            ("MESSAGE", &message),
+            ("PRIORITY", &priority),
+            ("TARGET", record.target()),
+            ("CODE_LINE", &line),
+        ];
+        if let Some(file) = record.file() {
+            fields.push(("CODE_FILE", file));
+        }
+
+        // Logging must never panic the caller; a dropped journal entry isn't fatal.
+        let _ = send_journal(&fields);
+    }
+
+    fn flush(&self) {}
+}
+
+static JOURNAL_LOGGER: JournalLog = JournalLog;
+
+// Installs JournalLog as the global logger for the `log` crate, routing the whole
+// ecosystem's log!()/info!()/etc. calls into the journal.
+pub fn init() -> Result<(), log::SetLoggerError> {
+    log::set_logger(&JOURNAL_LOGGER).map(|()| log::set_max_level(log::LevelFilter::Trace))
+}